@@ -1,5 +1,8 @@
 #![allow(dead_code, unused_variables, non_camel_case_types, non_snake_case)]
 
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
 #[derive(PartialEq)]
 pub enum LogLevel {
     INFO,
@@ -57,6 +60,162 @@ macro_rules! unwrap_bool {
     };
 }
 
+/// A cfg expression attached to an [`ExtraSource`], as understood by
+/// `rustc --print cfg`: bare identifiers (`unix`), `key = "value"` pairs
+/// (`target_os = "linux"`), and `all`/`any`/`not` combinators thereof.
+#[derive(Debug, Clone, PartialEq)]
+enum CfgExpr {
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+    Ident(String),
+    KeyValue(String, String),
+}
+
+fn cfg_skip_ws(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn cfg_parse_ident(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut ident = String::new();
+    while matches!(chars.peek(), Some(c) if c.is_alphanumeric() || *c == '_') {
+        ident.push(chars.next().unwrap());
+    }
+    ident
+}
+
+fn cfg_parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    chars.next(); // opening quote
+    let mut value = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            break;
+        }
+        value.push(c);
+    }
+    value
+}
+
+fn cfg_parse_expr(chars: &mut std::iter::Peekable<std::str::Chars>) -> CfgExpr {
+    cfg_skip_ws(chars);
+    let ident = cfg_parse_ident(chars);
+    cfg_skip_ws(chars);
+
+    match chars.peek() {
+        Some('(') => {
+            chars.next();
+            let mut args = Vec::new();
+            loop {
+                cfg_skip_ws(chars);
+                match chars.peek() {
+                    Some(&')') => {
+                        chars.next();
+                        break;
+                    }
+                    // Malformed/unbalanced cfg expression (missing closing
+                    // paren): stop instead of looping forever on an
+                    // exhausted iterator.
+                    None => break,
+                    _ => {}
+                }
+                args.push(cfg_parse_expr(chars));
+                cfg_skip_ws(chars);
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                }
+            }
+            match ident.as_str() {
+                "any" => CfgExpr::Any(args),
+                "not" => CfgExpr::Not(Box::new(
+                    args.into_iter().next().unwrap_or(CfgExpr::Ident(ident)),
+                )),
+                _ => CfgExpr::All(args),
+            }
+        }
+        Some('=') => {
+            chars.next();
+            cfg_skip_ws(chars);
+            CfgExpr::KeyValue(ident, cfg_parse_string(chars))
+        }
+        _ => CfgExpr::Ident(ident),
+    }
+}
+
+fn parse_cfg(input: &str) -> CfgExpr {
+    cfg_parse_expr(&mut input.chars().peekable())
+}
+
+fn eval_cfg(expr: &CfgExpr, cfg_set: &HashSet<(String, Option<String>)>) -> bool {
+    match expr {
+        CfgExpr::All(exprs) => exprs.iter().all(|expr| eval_cfg(expr, cfg_set)),
+        CfgExpr::Any(exprs) => exprs.iter().any(|expr| eval_cfg(expr, cfg_set)),
+        CfgExpr::Not(expr) => !eval_cfg(expr, cfg_set),
+        CfgExpr::Ident(name) => cfg_set.contains(&(name.clone(), None)),
+        CfgExpr::KeyValue(key, value) => cfg_set.contains(&(key.clone(), Some(value.clone()))),
+    }
+}
+
+/// Runs `rustc --print cfg` (passing through `--target` from `rustc_args`,
+/// if present) and parses its output into the set of active cfg keys/pairs.
+fn active_cfg_set(rustc_args: &[(&str, Option<&str>)]) -> HashSet<(String, Option<String>)> {
+    let mut command = std::process::Command::new("rustc");
+    command.args(["--print", "cfg"]);
+    if let Some((_, Some(target))) = rustc_args.iter().find(|(arg, _)| *arg == "--target") {
+        command.args(["--target", target]);
+    }
+
+    let output = match command.output() {
+        Ok(output) if output.status.success() => output,
+        _ => return HashSet::new(),
+    };
+
+    String::from_utf8(output.stdout)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.trim_matches('"').to_string())),
+            None => (line.to_string(), None),
+        })
+        .collect()
+}
+
+/// A source file passed to `rebuild*` as part of `extra_sources`.
+///
+/// Implemented for `&str`/`String` (always watched) and for `(&str, &str)`
+/// (path, cfg expression), so a source only participates in the rebuild
+/// check when its cfg expression matches the active target. Extra sources
+/// are not passed to `rustc`; only `main_path` is.
+pub trait ExtraSource {
+    fn path(&self) -> &str;
+    fn cfg(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl ExtraSource for &str {
+    fn path(&self) -> &str {
+        self
+    }
+}
+
+impl ExtraSource for String {
+    fn path(&self) -> &str {
+        self
+    }
+}
+
+impl<'a> ExtraSource for (&'a str, &'a str) {
+    fn path(&self) -> &str {
+        self.0
+    }
+
+    fn cfg(&self) -> Option<&str> {
+        Some(self.1)
+    }
+}
+
 fn needs_rebuild(output_path: &str, source_paths: &[&str]) -> bool {
     let output_meta = unwrap_bool!(std::fs::metadata(output_path));
 
@@ -71,12 +230,96 @@ fn needs_rebuild(output_path: &str, source_paths: &[&str]) -> bool {
     false
 }
 
+fn hash_file(path: &str) -> Option<u64> {
+    let contents = std::fs::read(path).ok()?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+fn hash_sources(source_paths: &[&str]) -> HashMap<String, u64> {
+    source_paths
+        .iter()
+        .filter_map(|path| hash_file(path).map(|hash| (path.to_string(), hash)))
+        .collect()
+}
+
+/// Reads `cache_path`'s `path -> content hash` sidecar, written by
+/// [`write_cache`]. Missing or unparseable entries are simply absent.
+fn read_cache(cache_path: &str) -> HashMap<String, u64> {
+    let Ok(contents) = std::fs::read_to_string(cache_path) else {
+        return HashMap::new();
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().trim_end_matches(',').split_once(':')?;
+            let key = key.trim().trim_matches('"').to_string();
+            let value = value.trim().trim_matches('"').parse().ok()?;
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn write_cache(cache_path: &str, hashes: &HashMap<String, u64>) -> std::io::Result<()> {
+    let mut entries: Vec<_> = hashes.iter().collect();
+    entries.sort_by_key(|(path, _)| path.as_str());
+
+    let mut body = String::from("{\n");
+    for (index, (path, hash)) in entries.iter().enumerate() {
+        let comma = if index + 1 == entries.len() { "" } else { "," };
+        body.push_str(&format!("  \"{path}\": \"{hash}\"{comma}\n"));
+    }
+    body.push_str("}\n");
+
+    std::fs::write(cache_path, body)
+}
+
+/// Computes the active cfg set (only if any `extra_sources` entry carries a
+/// cfg predicate) and the list of source paths to watch, filtering out
+/// `extra_sources` entries whose cfg predicate doesn't match.
+fn active_sources<'a, T>(main_path: &'a str, extra_sources: &'a [T], rustc_args: &[(&str, Option<&str>)]) -> Vec<&'a str>
+where
+    T: ExtraSource,
+{
+    let active_cfg = if extra_sources.iter().any(|source| source.cfg().is_some()) {
+        active_cfg_set(rustc_args)
+    } else {
+        HashSet::new()
+    };
+
+    let mut source_paths = vec![main_path];
+    source_paths.extend(extra_sources.iter().filter_map(|source| match source.cfg() {
+        Some(cfg) if !eval_cfg(&parse_cfg(cfg), &active_cfg) => None,
+        _ => Some(source.path()),
+    }));
+    source_paths
+}
+
+/// Like [`needs_rebuild`], but compares content hashes stored in
+/// `cache_path` instead of modification times, so a `git checkout` or a
+/// `touch` that doesn't change a source's content no longer triggers a
+/// spurious rebuild. Falls back to mtime comparison when `cache_path`
+/// doesn't exist yet (e.g. on the very first build).
+fn needs_rebuild_hashed(cache_path: &str, output_path: &str, source_paths: &[&str]) -> bool {
+    let cache = read_cache(cache_path);
+    if cache.is_empty() {
+        return needs_rebuild(output_path, source_paths);
+    }
+
+    source_paths.iter().any(|path| match hash_file(path) {
+        Some(hash) => cache.get(*path) != Some(&hash),
+        None => true,
+    })
+}
+
 /// Rebuilds the program with predefined edition (R2024) and O3 optimizations.
 ///
 /// First arg in `proc_args` must be the path to the executable.
 pub fn rebuild<T>(proc_args: &mut dyn Iterator<Item = String>, main_path: &str, extra_sources: &[T])
 where
-    T: AsRef<str>,
+    T: ExtraSource,
 {
     rebuild_edition(proc_args, RustEdition::R2024, main_path, extra_sources);
 }
@@ -90,7 +333,7 @@ pub fn rebuild_edition<T>(
     main_path: &str,
     extra_sources: &[T],
 ) where
-    T: AsRef<str>,
+    T: ExtraSource,
 {
     rebuild_edition_args(
         proc_args,
@@ -103,6 +346,10 @@ pub fn rebuild_edition<T>(
 
 /// Rebuilds the program with no additional flags and a custom edition.
 ///
+/// Sources in `extra_sources` carrying a cfg expression (see [`ExtraSource`])
+/// are only considered when that expression matches the active `rustc
+/// --print cfg` output, e.g. from `--target` in `rustc_args`.
+///
 /// First arg in `proc_args` must be the path to the executable.
 pub fn rebuild_edition_args<T>(
     proc_args: &mut dyn Iterator<Item = String>,
@@ -111,18 +358,61 @@ pub fn rebuild_edition_args<T>(
     extra_sources: &[T],
     rustc_args: &[(&str, Option<&str>)],
 ) where
-    T: AsRef<str>,
+    T: ExtraSource,
 {
     let self_path = match proc_args.next() {
         Some(self_path) => self_path,
         None => return,
     };
-    let mut source_paths = vec![main_path];
-    source_paths.append(&mut extra_sources.iter().map(|path| path.as_ref()).collect());
+
+    let source_paths = active_sources(main_path, extra_sources, rustc_args);
     if !needs_rebuild(&self_path, &source_paths) {
         return;
     }
 
+    perform_rebuild(proc_args, &self_path, &edition, main_path, rustc_args, || {});
+}
+
+/// Like [`rebuild_edition_args`], but uses content-hash change detection
+/// (see [`needs_rebuild_hashed`]) instead of file mtimes, storing hashes in
+/// `cache_path` (e.g. `.cnot-cache.json`).
+///
+/// First arg in `proc_args` must be the path to the executable.
+pub fn rebuild_edition_args_cached<T>(
+    proc_args: &mut dyn Iterator<Item = String>,
+    edition: RustEdition,
+    main_path: &str,
+    extra_sources: &[T],
+    rustc_args: &[(&str, Option<&str>)],
+    cache_path: &str,
+) where
+    T: ExtraSource,
+{
+    let self_path = match proc_args.next() {
+        Some(self_path) => self_path,
+        None => return,
+    };
+
+    let source_paths = active_sources(main_path, extra_sources, rustc_args);
+    if !needs_rebuild_hashed(cache_path, &self_path, &source_paths) {
+        return;
+    }
+
+    perform_rebuild(proc_args, &self_path, &edition, main_path, rustc_args, || {
+        if let Err(err) = write_cache(cache_path, &hash_sources(&source_paths)) {
+            log!(LogLevel::WARNING, "Failed to update {cache_path}: {err}");
+        }
+    });
+}
+
+fn perform_rebuild(
+    proc_args: &mut dyn Iterator<Item = String>,
+    self_path: &str,
+    edition: &RustEdition,
+    main_path: &str,
+    rustc_args: &[(&str, Option<&str>)],
+    on_success: impl FnOnce(),
+) -> ! {
     let mut args = vec![];
     for (arg, value) in rustc_args {
         args.push(arg);
@@ -133,13 +423,7 @@ pub fn rebuild_edition_args<T>(
 
     let status = std::process::Command::new("rustc")
         .args(args)
-        .args([
-            "--edition",
-            &edition.to_string(),
-            "-o",
-            &self_path,
-            main_path,
-        ])
+        .args(["--edition", &edition.to_string(), "-o", self_path, main_path])
         .status()
         .expect("failed to rebuild");
 
@@ -149,7 +433,8 @@ pub fn rebuild_edition_args<T>(
     }
 
     log!(LogLevel::INFO, "Build successful");
-    std::process::Command::new(&self_path)
+    on_success();
+    std::process::Command::new(self_path)
         .args(proc_args)
         .spawn()
         .expect("program failed to run")
@@ -158,8 +443,35 @@ pub fn rebuild_edition_args<T>(
     std::process::exit(0);
 }
 
+/// A dependency edge in a [`CrateSpec`]'s `deps`: the depended-on crate's
+/// index into the `crates` slice passed to [`generate_project_with_deps`],
+/// plus the name it's imported under (`extern crate <name>` / `use <name>`).
+pub struct CrateDep<'a> {
+    pub name: &'a str,
+    pub index: usize,
+}
+
+/// One `rust-project.json` crate entry.
+pub struct CrateSpec<'a> {
+    pub root_module: &'a str,
+    pub edition: RustEdition,
+    pub deps: &'a [CrateDep<'a>],
+}
+
 /// Generates `rust-project.json` to fix rust-analyzer not working on standalone files.
 pub fn generate_project(root_file: &str, edition: RustEdition) -> std::io::Result<()> {
+    generate_project_with_deps(&[CrateSpec {
+        root_module: root_file,
+        edition,
+        deps: &[],
+    }])
+}
+
+/// Like [`generate_project`], but emits one `rust-project.json` crate entry
+/// per [`CrateSpec`], with populated `deps` arrays, so a `cnot` project can
+/// span several standalone files that depend on one another and still
+/// resolve in rust-analyzer.
+pub fn generate_project_with_deps(crates: &[CrateSpec]) -> std::io::Result<()> {
     if std::fs::exists("rust-project.json")? {
         return Ok(());
     }
@@ -174,26 +486,43 @@ pub fn generate_project(root_file: &str, edition: RustEdition) -> std::io::Resul
     }
     let sysroot_path = String::from_utf8(sysroot_path.stdout).unwrap();
     let mut sysroot_path = sysroot_path.lines();
+    let sysroot_src = format!(
+        "{}/lib/rustlib/src/rust/library",
+        sysroot_path.next().unwrap()
+    );
+
+    let crates_json: Vec<String> = crates
+        .iter()
+        .map(|krate| {
+            let deps_json: Vec<String> = krate
+                .deps
+                .iter()
+                .map(|dep| format!(r#"{{"crate": {}, "name": "{}"}}"#, dep.index, dep.name))
+                .collect();
+            format!(
+                r#"    {{
+        "root_module": "{}",
+        "edition": "{}",
+        "deps": [{}]
+    }}"#,
+                krate.root_module,
+                krate.edition,
+                deps_json.join(", ")
+            )
+        })
+        .collect();
 
     std::fs::write(
         "rust-project.json",
-        &format!(
+        format!(
             r#"{{
 "sysroot_src": "{}",
 "crates": [
-    {{
-        "root_module": "{}",
-        "edition": "{}",
-        "deps": []
-    }}
+{}
 ]
 }}"#,
-            format!(
-                "{}/lib/rustlib/src/rust/library",
-                sysroot_path.next().unwrap()
-            ),
-            root_file,
-            edition
+            sysroot_src,
+            crates_json.join(",\n")
         ),
     )?;
     Ok(())
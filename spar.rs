@@ -1,16 +1,43 @@
+use std::cell::Cell;
 use std::sync::Mutex;
 
 static FLAGS: Mutex<Vec<Flag>> = Mutex::new(Vec::new());
+static SUBCOMMANDS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+static ACTIVE_COMMAND: Mutex<Option<&'static str>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Positional {
+    Single,
+    Repeated,
+}
 
 #[derive(Debug)]
 pub struct Flag {
     name: &'static str,
     value: FlagValue,
+    command: Option<&'static str>,
+    description: Cell<Option<&'static str>>,
+    positional: Option<Positional>,
+    required: Cell<bool>,
+    was_set: Cell<bool>,
 }
 
 impl Flag {
-    fn new(name: &'static str, value: FlagValue) -> Self {
-        Self { name, value }
+    fn new(
+        name: &'static str,
+        value: FlagValue,
+        command: Option<&'static str>,
+        positional: Option<Positional>,
+    ) -> Self {
+        Self {
+            name,
+            value,
+            command,
+            description: Cell::new(None),
+            positional,
+            required: Cell::new(false),
+            was_set: Cell::new(false),
+        }
     }
 
     pub fn name(&self) -> &str {
@@ -20,6 +47,39 @@ impl Flag {
     pub fn value(&self) -> &FlagValue {
         &self.value
     }
+
+    /// The subcommand this flag is scoped to, or `None` for a global flag.
+    pub fn command(&self) -> Option<&'static str> {
+        self.command
+    }
+
+    /// Attach a description shown by [`print_usage`]/[`help_string`]
+    pub fn help(&self, description: &'static str) -> &Self {
+        self.description.set(Some(description));
+        self
+    }
+
+    pub fn description(&self) -> Option<&'static str> {
+        self.description.get()
+    }
+
+    /// Mark this flag as required; `try_parse_args` errors if it is never
+    /// supplied on the command line. A subcommand-scoped flag is only
+    /// checked when its subcommand is active.
+    pub fn required(&self) -> &Self {
+        self.required.set(true);
+        self
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.required.get()
+    }
+
+    /// Whether this flag was actually supplied on the command line, as
+    /// opposed to still holding its default value.
+    pub fn was_set(&self) -> bool {
+        self.was_set.get()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -30,6 +90,21 @@ pub enum FlagValue {
     Float(f32),
     Double(f64),
     String(String),
+    List(Vec<String>),
+}
+
+impl FlagValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Bool(_) => "bool",
+            Self::Long(_) => "long",
+            Self::ULong(_) => "ulong",
+            Self::Float(_) => "float",
+            Self::Double(_) => "double",
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+        }
+    }
 }
 
 impl std::fmt::Display for FlagValue {
@@ -41,12 +116,106 @@ impl std::fmt::Display for FlagValue {
             Self::Float(value) => f.write_fmt(format_args!("{value}")),
             Self::Double(value) => f.write_fmt(format_args!("{value}")),
             Self::String(value) => f.write_fmt(format_args!("\"{}\"", &value)),
+            Self::List(values) => f.write_fmt(format_args!("[{}]", values.join(", "))),
         }
     }
 }
 
+fn assign_positional(
+    flags: &mut [Flag],
+    positionals: &[usize],
+    cursor: &mut usize,
+    satisfied: &mut [bool],
+    value: String,
+) {
+    let Some(&index) = positionals.get(*cursor) else {
+        return;
+    };
+
+    flags[index].was_set.set(true);
+    match &mut flags[index].value {
+        FlagValue::List(values) => values.push(value),
+        _ => {
+            flags[index].value = FlagValue::String(value);
+            satisfied[*cursor] = true;
+            *cursor += 1;
+        }
+    }
+}
+
+/// Error returned by [`try_parse_args`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    UnknownFlag(String),
+    MissingValue { flag: String },
+    InvalidValue {
+        flag: String,
+        input: String,
+        expected: &'static str,
+    },
+    MissingPositional,
+    MissingRequiredFlags(Vec<String>),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::UnknownFlag(flag) => write!(f, "unknown flag: -{flag}"),
+            Self::MissingValue { flag } => write!(f, "missing value for flag: -{flag}"),
+            Self::InvalidValue {
+                flag,
+                input,
+                expected,
+            } => write!(f, "invalid value \"{input}\" for flag -{flag}: expected {expected}"),
+            Self::MissingPositional => write!(f, "missing required positional argument"),
+            Self::MissingRequiredFlags(flags) => {
+                let flags: Vec<String> = flags.iter().map(|flag| format!("-{flag}")).collect();
+                write!(f, "missing required flags: {}", flags.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse `proc_args`, printing an error and exiting non-zero on failure.
+///
+/// See [`try_parse_args`] for a version that reports errors instead of
+/// exiting the process.
 pub fn parse_args(proc_args: &mut dyn Iterator<Item = String>) {
+    if let Err(err) = try_parse_args(proc_args) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+/// Parse `proc_args`, returning a [`ParseError`] instead of panicking or
+/// exiting on a missing value, malformed input, unknown flag, or unfilled
+/// positional.
+pub fn try_parse_args(proc_args: &mut dyn Iterator<Item = String>) -> Result<(), ParseError> {
+    // The first token is the path to the running executable (the same
+    // convention `cnot`'s rebuild functions use), not an argument to parse.
+    proc_args.next();
+
     let mut flags = FLAGS.lock().unwrap();
+    let subcommands = SUBCOMMANDS.lock().unwrap();
+    let mut active_command = ACTIVE_COMMAND.lock().unwrap();
+    *active_command = None;
+    let mut subcommand_resolved = subcommands.is_empty();
+    let positionals: Vec<usize> = flags
+        .iter()
+        .enumerate()
+        .filter(|(_, flag)| flag.positional.is_some())
+        .map(|(index, _)| index)
+        .collect();
+    let mut positional_cursor = 0;
+    // Repeated positionals absorb zero or more tokens, so they start out
+    // satisfied; only `Single` positionals require an explicit value.
+    let mut positional_satisfied: Vec<bool> = positionals
+        .iter()
+        .map(|&index| flags[index].positional == Some(Positional::Repeated))
+        .collect();
+
     while let Some(arg) = proc_args.next() {
         if arg.len() < 2 {
             continue;
@@ -54,6 +223,20 @@ pub fn parse_args(proc_args: &mut dyn Iterator<Item = String>) {
 
         let mut chars = arg.chars().peekable();
         if chars.next().unwrap() != '-' {
+            if !subcommand_resolved {
+                subcommand_resolved = true;
+                if let Some(command) = subcommands.iter().find(|command| ***command == arg) {
+                    *active_command = Some(command);
+                    continue;
+                }
+            }
+            assign_positional(
+                &mut flags,
+                &positionals,
+                &mut positional_cursor,
+                &mut positional_satisfied,
+                arg,
+            );
             continue;
         }
 
@@ -66,11 +249,27 @@ pub fn parse_args(proc_args: &mut dyn Iterator<Item = String>) {
             name.push(c);
         }
 
+        if name == "help" || name == "h" {
+            print!("{}", format_usage(&flags));
+            std::process::exit(0);
+        }
+
+        let mut matched = false;
         for flag in flags.iter_mut() {
             if flag.name != &name {
                 continue;
             }
 
+            if let Some(command) = flag.command {
+                if *active_command != Some(command) {
+                    continue;
+                }
+            }
+
+            matched = true;
+            if !ignore {
+                flag.was_set.set(true);
+            }
             match &mut flag.value {
                 FlagValue::Bool(value) => {
                     if !ignore {
@@ -78,49 +277,158 @@ pub fn parse_args(proc_args: &mut dyn Iterator<Item = String>) {
                     }
                 }
                 FlagValue::Long(value) => {
-                    let arg = proc_args.next().unwrap();
+                    let arg = proc_args
+                        .next()
+                        .ok_or_else(|| ParseError::MissingValue { flag: name.clone() })?;
                     if !ignore {
-                        *value = arg.parse().unwrap();
+                        *value = arg.parse().map_err(|_| ParseError::InvalidValue {
+                            flag: name.clone(),
+                            input: arg.clone(),
+                            expected: "long",
+                        })?;
                     }
                 }
                 FlagValue::ULong(value) => {
-                    let arg = proc_args.next().unwrap();
+                    let arg = proc_args
+                        .next()
+                        .ok_or_else(|| ParseError::MissingValue { flag: name.clone() })?;
                     if !ignore {
-                        *value = arg.parse().unwrap();
+                        *value = arg.parse().map_err(|_| ParseError::InvalidValue {
+                            flag: name.clone(),
+                            input: arg.clone(),
+                            expected: "ulong",
+                        })?;
                     }
                 }
                 FlagValue::Float(value) => {
-                    let arg = proc_args.next().unwrap();
+                    let arg = proc_args
+                        .next()
+                        .ok_or_else(|| ParseError::MissingValue { flag: name.clone() })?;
                     if !ignore {
-                        *value = arg.parse().unwrap();
+                        *value = arg.parse().map_err(|_| ParseError::InvalidValue {
+                            flag: name.clone(),
+                            input: arg.clone(),
+                            expected: "float",
+                        })?;
                     }
                 }
                 FlagValue::Double(value) => {
-                    let arg = proc_args.next().unwrap();
+                    let arg = proc_args
+                        .next()
+                        .ok_or_else(|| ParseError::MissingValue { flag: name.clone() })?;
                     if !ignore {
-                        *value = arg.parse().unwrap();
+                        *value = arg.parse().map_err(|_| ParseError::InvalidValue {
+                            flag: name.clone(),
+                            input: arg.clone(),
+                            expected: "double",
+                        })?;
                     }
                 }
                 FlagValue::String(value) => {
-                    let arg = proc_args.next().unwrap();
-                    if ignore {
-                        continue;
-                    }
-
-                    if arg.starts_with("\"") {
-                        *value = arg[1..arg.len() - 1].to_string();
-                    } else {
-                        *value = arg;
+                    let arg = proc_args
+                        .next()
+                        .ok_or_else(|| ParseError::MissingValue { flag: name.clone() })?;
+                    if !ignore {
+                        if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+                            *value = arg[1..arg.len() - 1].to_string();
+                        } else {
+                            *value = arg;
+                        }
                     }
                 }
+                FlagValue::List(_) => {}
             }
+
+            // Stop at the first name- and scope-matching flag: where a global
+            // and an active subcommand-scoped flag share a name, only the one
+            // registered first consumes this occurrence's value.
+            break;
         }
+
+        if !matched {
+            return Err(ParseError::UnknownFlag(name));
+        }
+    }
+
+    if positional_satisfied.iter().any(|&satisfied| !satisfied) {
+        return Err(ParseError::MissingPositional);
     }
+
+    let missing_required: Vec<String> = flags
+        .iter()
+        .filter(|flag| {
+            flag.required.get()
+                && !flag.was_set.get()
+                && (flag.command.is_none() || flag.command == *active_command)
+        })
+        .map(|flag| flag.name.to_string())
+        .collect();
+    if !missing_required.is_empty() {
+        return Err(ParseError::MissingRequiredFlags(missing_required));
+    }
+
+    Ok(())
 }
 
-fn new_flag(name: &'static str, value: FlagValue) -> &'static Flag {
+fn format_usage(flags: &[Flag]) -> String {
+    let rows: Vec<(String, &'static str, String, &'static str)> = flags
+        .iter()
+        .map(|flag| {
+            let name = match (flag.command, flag.positional) {
+                (_, Some(Positional::Single)) => format!("<{}>", flag.name),
+                (_, Some(Positional::Repeated)) => format!("<{}>...", flag.name),
+                (Some(command), None) => format!("-{} [{command}]", flag.name),
+                (None, None) => format!("-{}", flag.name),
+            };
+            (
+                name,
+                flag.value.type_name(),
+                flag.value.to_string(),
+                flag.description.get().unwrap_or(""),
+            )
+        })
+        .collect();
+
+    let name_width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+    let type_width = rows.iter().map(|(_, kind, ..)| kind.len()).max().unwrap_or(0);
+    let default_width = rows
+        .iter()
+        .map(|(_, _, default, _)| default.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut usage = String::from("USAGE:\n");
+    for (name, kind, default, description) in &rows {
+        usage.push_str(&format!(
+            "  {name:name_width$}  {kind:type_width$}  {default:default_width$}  {description}\n"
+        ));
+    }
+    usage
+}
+
+/// Render the generated usage/help text for every registered flag.
+pub fn help_string() -> String {
+    let flags = FLAGS.lock().unwrap();
+    format_usage(&flags)
+}
+
+/// Print the generated usage/help text for every registered flag.
+pub fn print_usage() {
+    print!("{}", help_string());
+}
+
+fn new_flag(command: Option<&'static str>, name: &'static str, value: FlagValue) -> &'static Flag {
+    new_flag_inner(command, name, value, None)
+}
+
+fn new_flag_inner(
+    command: Option<&'static str>,
+    name: &'static str,
+    value: FlagValue,
+    positional: Option<Positional>,
+) -> &'static Flag {
     let mut flags = FLAGS.lock().unwrap();
-    flags.push(Flag::new(name, value));
+    flags.push(Flag::new(name, value, command, positional));
     let ptr = flags.last().unwrap() as *const _;
     unsafe { &*ptr }
 }
@@ -129,27 +437,27 @@ fn new_flag(name: &'static str, value: FlagValue) -> &'static Flag {
 ///
 /// This flag works like a toggle, i.e. value = !value
 pub fn flag_bool(name: &'static str, default_value: bool) -> &'static Flag {
-    new_flag(name, FlagValue::Bool(default_value))
+    new_flag(None, name, FlagValue::Bool(default_value))
 }
 
 /// Create a new long flag
 pub fn flag_long(name: &'static str, default_value: i64) -> &'static Flag {
-    new_flag(name, FlagValue::Long(default_value))
+    new_flag(None, name, FlagValue::Long(default_value))
 }
 
 /// Create a new ulong flag
 pub fn flag_ulong(name: &'static str, default_value: u64) -> &'static Flag {
-    new_flag(name, FlagValue::ULong(default_value))
+    new_flag(None, name, FlagValue::ULong(default_value))
 }
 
 /// Create a new float flag
 pub fn flag_float(name: &'static str, default_value: f32) -> &'static Flag {
-    new_flag(name, FlagValue::Float(default_value))
+    new_flag(None, name, FlagValue::Float(default_value))
 }
 
 /// Create a new double flag
 pub fn flag_double(name: &'static str, default_value: f64) -> &'static Flag {
-    new_flag(name, FlagValue::Double(default_value))
+    new_flag(None, name, FlagValue::Double(default_value))
 }
 
 /// Create a new string flag
@@ -158,5 +466,91 @@ pub fn flag_double(name: &'static str, default_value: f64) -> &'static Flag {
 /// - content
 /// - "content"
 pub fn flag_string(name: &'static str, default_value: &str) -> &'static Flag {
-    new_flag(name, FlagValue::String(default_value.to_string()))
+    new_flag(None, name, FlagValue::String(default_value.to_string()))
+}
+
+/// Create a new positional flag
+///
+/// Captures the next unclaimed non-flag token, in registration order.
+pub fn flag_positional(name: &'static str) -> &'static Flag {
+    new_flag_inner(
+        None,
+        name,
+        FlagValue::String(String::new()),
+        Some(Positional::Single),
+    )
+}
+
+/// Create a new repeated positional flag
+///
+/// Greedily absorbs every remaining unclaimed non-flag token into a
+/// `FlagValue::List`, so it should be registered last.
+pub fn flag_positional_repeated(name: &'static str) -> &'static Flag {
+    new_flag_inner(
+        None,
+        name,
+        FlagValue::List(Vec::new()),
+        Some(Positional::Repeated),
+    )
+}
+
+/// A handle returned by [`subcommand`] under which flags can be registered
+/// that only apply when that subcommand is active.
+pub struct Subcommand {
+    name: &'static str,
+}
+
+impl Subcommand {
+    /// Create a new boolean flag scoped to this subcommand
+    pub fn flag_bool(&self, name: &'static str, default_value: bool) -> &'static Flag {
+        new_flag(Some(self.name), name, FlagValue::Bool(default_value))
+    }
+
+    /// Create a new long flag scoped to this subcommand
+    pub fn flag_long(&self, name: &'static str, default_value: i64) -> &'static Flag {
+        new_flag(Some(self.name), name, FlagValue::Long(default_value))
+    }
+
+    /// Create a new ulong flag scoped to this subcommand
+    pub fn flag_ulong(&self, name: &'static str, default_value: u64) -> &'static Flag {
+        new_flag(Some(self.name), name, FlagValue::ULong(default_value))
+    }
+
+    /// Create a new float flag scoped to this subcommand
+    pub fn flag_float(&self, name: &'static str, default_value: f32) -> &'static Flag {
+        new_flag(Some(self.name), name, FlagValue::Float(default_value))
+    }
+
+    /// Create a new double flag scoped to this subcommand
+    pub fn flag_double(&self, name: &'static str, default_value: f64) -> &'static Flag {
+        new_flag(Some(self.name), name, FlagValue::Double(default_value))
+    }
+
+    /// Create a new string flag scoped to this subcommand
+    ///
+    /// Accepted input values:
+    /// - content
+    /// - "content"
+    pub fn flag_string(&self, name: &'static str, default_value: &str) -> &'static Flag {
+        new_flag(
+            Some(self.name),
+            name,
+            FlagValue::String(default_value.to_string()),
+        )
+    }
+}
+
+/// Register a subcommand, e.g. `myprog build -O 3 file.rs` vs `myprog clean`.
+///
+/// The leading non-dash token in `parse_args` is matched against registered
+/// subcommand names; flags registered through the returned handle only apply
+/// while that subcommand is active.
+pub fn subcommand(name: &'static str) -> Subcommand {
+    SUBCOMMANDS.lock().unwrap().push(name);
+    Subcommand { name }
+}
+
+/// The subcommand matched during the last `parse_args` call, if any.
+pub fn active_command() -> Option<&'static str> {
+    *ACTIVE_COMMAND.lock().unwrap()
 }